@@ -4,17 +4,28 @@ use tracer::TracerData;
 use std::convert::From;
 use std::default::Default;
 use std::collections::{HashSet, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use nix::Error as NixErr;
+use nix::errno::Errno;
 use nix::unistd::*;
 use nix::sys::ptrace::ptrace::*;
 use nix::sys::signal;
 use nix::sys::wait::*;
 use procinfo::pid::{stat, status, Stat, Status};
-use nix::libc::pid_t;
+use nix::libc;
+use nix::libc::{c_int, pid_t};
 
 #[derive(Eq, PartialEq, Debug)]
 struct RunState {
     wait_signal: WaitStatus,
+    // True when this was a syscall-entry/exit stop (WSTOPSIG == SIGTRAP|0x80),
+    // which nix's `Signal` can't represent so it is decoded from the raw word.
+    syscall_stop: bool,
     child_stats: Option<Stat>,
     child_status: Option<Status>,
 }
@@ -23,59 +34,255 @@ impl Default for RunState {
     fn default() -> Self {
         RunState {
             wait_signal: WaitStatus::StillAlive,
+            syscall_stop: false,
             child_stats: None,
             child_status: None,
         }
     }
 }
 
-impl From<WaitStatus> for RunState {
-    fn from(wait: WaitStatus) -> Self {
-        let pid = match wait {
-            WaitStatus::Exited(p, _) => pid_t::from(p),
-            WaitStatus::Signaled(p, _, _) => pid_t::from(p),
-            WaitStatus::Stopped(p, _) => pid_t::from(p),
-            WaitStatus::Continued(p) => pid_t::from(p),
-            _ => 0,
+impl RunState {
+    /// Decodes a raw `waitpid` status word for `child`, picking off the
+    /// syscall-good stop before handing the rest to nix's decoder.
+    fn from_raw(child: Pid, raw: c_int) -> Self {
+        let syscall_stop = libc::WIFSTOPPED(raw)
+            && libc::WSTOPSIG(raw) == (libc::SIGTRAP | 0x80);
+        let wait_signal = if syscall_stop {
+            WaitStatus::Stopped(child, signal::SIGTRAP)
+        } else {
+            WaitStatus::from_raw(child, raw).unwrap_or(WaitStatus::StillAlive)
         };
+        let pid = pid_t::from(child);
         RunState {
+            wait_signal,
+            syscall_stop,
             child_stats: stat(pid).ok(),
             child_status: status(pid).ok(),
-            wait_signal: wait
         }
     }
 }
 
 
-fn wait_state() -> Result<RunState, NixErr> {
-    let step = waitpid(Pid::from_raw(-1), Some(__WALL))?;
-    Ok(RunState::from(step))
+/// A run error: the usual nix errors, plus a `Timeout` the caller can act on.
+#[derive(Debug)]
+pub enum RunError {
+    Nix(NixErr),
+    Timeout,
+}
+
+impl From<NixErr> for RunError {
+    fn from(err: NixErr) -> Self {
+        RunError::Nix(err)
+    }
+}
+
+/// How long to sleep between `WNOHANG` polls while waiting on a deadline.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Counts the SIGINTs received since the current `run_function` call started
+/// (it resets the count on entry). The first drains
+/// partial coverage, the second aborts hard.
+static INTERRUPTS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+extern "C" fn handle_sigint(_: i32) {
+    INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Restores the SIGINT handler when a run ends, whatever path it exits by.
+struct SigintGuard {
+    previous: signal::SigAction,
+}
+
+impl Drop for SigintGuard {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = signal::sigaction(signal::SIGINT, &self.previous);
+        }
+    }
+}
+
+
+fn wait_state(deadline: Option<Instant>) -> Result<RunState, RunError> {
+    // Wait through raw libc so we can see a SIGTRAP|0x80 syscall-stop, which
+    // nix's `Signal`-based decode rejects. `WNOHANG` is only set when there is
+    // a deadline to honour, otherwise the wait blocks as before.
+    let flags = libc::__WALL | if deadline.is_some() { libc::WNOHANG } else { 0 };
+    loop {
+        let mut raw: c_int = 0;
+        let ret = unsafe { libc::waitpid(-1, &mut raw as *mut c_int, flags) };
+        if ret == -1 {
+            return Err(RunError::Nix(NixErr::Sys(Errno::last())));
+        }
+        if ret == 0 {
+            // Nothing ready yet; only reachable with WNOHANG, i.e. a deadline.
+            match deadline {
+                Some(d) if Instant::now() >= d => return Err(RunError::Timeout),
+                _ => {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            }
+        }
+        return Ok(RunState::from_raw(Pid::from_raw(ret), raw));
+    }
 }
 
 
-fn check_parents(parents: &HashSet<Pid>, current: Pid) -> bool {
-    parents.contains(&Pid::from_raw(0)) 
+/// SIGKILLs the root tracee and every descendant we know about.
+fn kill_tree(root: Pid, tree: &HashMap<Pid, Pid>) {
+    let _ = signal::kill(root, signal::SIGKILL);
+    for child in tree.keys() {
+        let _ = signal::kill(*child, signal::SIGKILL);
+    }
 }
 
 
-fn handle_trap(pid: Pid, 
-               no_count:bool, 
-               thread_count: isize,
-               unwarned: &mut bool,
-               mut traces: &mut Vec<TracerData>, 
+/// Restores the original bytes and detaches from the whole tree, so the
+/// processes run on normally once we let go of them.
+fn detach_tree(root: Pid,
+               tree: &HashMap<Pid, Pid>,
+               breakpoints: &mut HashMap<u64, Breakpoint>) {
+    let mut pids: Vec<Pid> = tree.keys().cloned().collect();
+    pids.push(root);
+    for pid in pids {
+        // The tracees are running by the time we get here, but disabling a
+        // breakpoint and PTRACE_DETACH both need the target ptrace-stopped.
+        // Stop it and reap the resulting signal-delivery-stop first.
+        let _ = signal::kill(pid, signal::SIGSTOP);
+        let _ = waitpid(pid, Some(__WALL));
+        for bp in breakpoints.values_mut() {
+            let _ = bp.disable(pid);
+        }
+        let _ = detach_child(pid);
+        // Clear the SIGSTOP we injected so the now-detached process keeps
+        // running instead of sitting stopped.
+        let _ = signal::kill(pid, signal::SIGCONT);
+    }
+}
+
+
+/// A syscall issued by a traced thread: `origin_rip` invoked it, `origin` is
+/// the mapped file (shared object or executable) that contains that address.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SyscallData {
+    pub nr: u64,
+    pub count: u64,
+    pub origin_rip: u64,
+    pub origin: Option<PathBuf>,
+}
+
+
+/// Folds a syscall into `syscalls`, bumping the count of an existing entry
+/// with the same number and invoking address (`origin_rip`) or pushing a new
+/// one.
+fn record_syscall(syscalls: &mut Vec<SyscallData>,
+                  nr: u64,
+                  rip: u64,
+                  origin: Option<PathBuf>) {
+    if let Some(data) = syscalls.iter_mut()
+                                .find(|s| s.nr == nr && s.origin_rip == rip) {
+        data.count += 1;
+    } else {
+        syscalls.push(SyscallData { nr, count: 1, origin_rip: rip, origin });
+    }
+}
+
+
+/// Resumes a stopped tracee, stepping to the next syscall entry/exit when
+/// syscall tracing is enabled and otherwise running freely to the next trap.
+fn resume(pid: Pid,
+          sig: Option<signal::Signal>,
+          trace_syscalls: bool) -> Result<(), NixErr> {
+    if trace_syscalls {
+        continue_syscall(pid, sig)
+    } else {
+        continue_exec(pid, sig)
+    }
+}
+
+
+/// Maps an address in `pid`'s space to the shared object / file that backs it
+/// by scanning `/proc/<pid>/maps` for the region that contains `addr`.
+fn module_for_addr(pid: Pid, addr: u64) -> Option<PathBuf> {
+    let file = File::open(format!("/proc/{}/maps", pid_t::from(pid))).ok()?;
+    for line in BufReader::new(file).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => break,
+        };
+        // "<start>-<end> perms offset dev inode   <pathname>"
+        let mut fields = line.split_whitespace();
+        let range = match fields.next() {
+            Some(r) => r,
+            None => continue,
+        };
+        let mut bounds = range.split('-');
+        let start = bounds.next().and_then(|s| u64::from_str_radix(s, 16).ok());
+        let end = bounds.next().and_then(|s| u64::from_str_radix(s, 16).ok());
+        if let (Some(start), Some(end)) = (start, end) {
+            if addr >= start && addr < end {
+                // perms, offset, dev, inode, then the optional pathname.
+                return fields.nth(4).map(PathBuf::from);
+            }
+        }
+    }
+    None
+}
+
+
+/// Records a syscall stop for `pid`, attributing it to the shared object that
+/// owns the invoking instruction.
+fn handle_syscall(pid: Pid, syscalls: &mut Vec<SyscallData>) {
+    let nr = match get_syscall_number(pid) {
+        Ok(nr) => nr,
+        Err(_) => return,
+    };
+    let rip = current_instruction_pointer(pid).map(|r| r as u64).unwrap_or(0);
+    let origin = module_for_addr(pid, rip);
+    record_syscall(syscalls, nr, rip, origin);
+}
+
+
+/// True if `current`, or any ancestor reached by walking `tree`, is in
+/// `parents`.
+fn check_parents(parents: &HashSet<Pid>,
+                 current: Pid,
+                 tree: &HashMap<Pid, Pid>) -> bool {
+    let mut node = current;
+    loop {
+        if parents.contains(&node) {
+            return true;
+        }
+        match tree.get(&node) {
+            Some(&parent) => node = parent,
+            None => return false,
+        }
+    }
+}
+
+
+fn handle_trap(pid: Pid,
+               no_count: bool,
+               trace_syscalls: bool,
+               threads: &HashSet<Pid>,
+               mut traces: &mut Vec<TracerData>,
                mut breakpoints: &mut HashMap<u64, Breakpoint>) -> Result<(), NixErr> {
-  
+
     if let Ok(rip) = current_instruction_pointer(pid) {
         let rip = (rip - 1) as u64;
         if  breakpoints.contains_key(&rip) {
             let bp = &mut breakpoints.get_mut(&rip).unwrap();
-            let enable = (!no_count) && (thread_count < 2);
-            if !enable && *unwarned {
-                println!("Code is mulithreaded, disabling hit count");
-                *unwarned = false;
-            }
-            // Don't reenable if multithreaded as can't yet sort out segfault issue
-            let updated = if let Ok(x) = bp.process(pid, enable) {
+            // Freeze the other threads sharing this address space while we
+            // clear the int3, single-step across it and re-arm it, otherwise a
+            // sibling can race through the cleared address. Only the tracked
+            // thread group counts: a forked child is a separate process and
+            // must not drag the parent's threads into a group-stop.
+            let siblings: HashSet<Pid> = if threads.contains(&pid) {
+                threads.iter().cloned().filter(|p| *p != pid).collect()
+            } else {
+                HashSet::new()
+            };
+            let updated = if let Ok(x) = bp.process(pid, &siblings, !no_count) {
                  x
             } else {
                 false
@@ -85,11 +292,11 @@ fn handle_trap(pid: Pid,
                                    .filter(|x| x.address == Some(rip)) {
                     (*t).hits += 1;
                 }
-            } 
+            }
         } else {
-            continue_exec(pid, None)?;
+            resume(pid, None, trace_syscalls)?;
         }
-    } 
+    }
     Ok(())
 }
 
@@ -99,21 +306,80 @@ fn handle_trap(pid: Pid,
 pub fn run_function(pid: Pid,
                     forward_signals: bool,
                     no_count: bool,
+                    follow_forks: bool,
+                    trace_syscalls: bool,
+                    timeout: Option<Duration>,
                     mut traces: &mut Vec<TracerData>,
-                    mut breakpoints: &mut HashMap<u64, Breakpoint>) -> Result<i8, NixErr> {
+                    mut syscalls: &mut Vec<SyscallData>,
+                    mut breakpoints: &mut HashMap<u64, Breakpoint>) -> Result<i8, RunError> {
     let mut res = 0i8;
-    // Thread count, don't count initial thread of execution
-    let mut thread_count = 0isize;
-    let mut unwarned = !no_count;
-    // Start the function running. 
-    continue_exec(pid, None)?;
+    // Every live thread of the tracee, including the initial one. Kept accurate
+    // via CLONE/exit events so breakpoint servicing knows which siblings to
+    // freeze while single-stepping.
+    let mut threads: HashSet<Pid> = HashSet::new();
+    threads.insert(pid);
+    // Opt the tracee into PTRACE_O_TRACESYSGOOD so syscall-stops arrive as
+    // SIGTRAP|0x80 and can be told apart from breakpoint traps.
+    if trace_syscalls {
+        set_trace_syscalls(pid)?;
+    }
+    // Start the function running.
+    resume(pid, None, trace_syscalls)?;
     let mut ignored_parents: HashSet<Pid> = HashSet::new();
+    // Maps a child pid to the pid which spawned it so we can decide whether a
+    // stopped process is a descendant of the test binary.
+    let mut process_tree: HashMap<Pid, Pid> = HashMap::new();
+    // Forked children awaiting their initial stop before we re-arm breakpoints.
+    let mut pending_forks: HashSet<Pid> = HashSet::new();
+    // Forked children awaiting their initial stop before we detach them.
+    let mut pending_detach: HashSet<Pid> = HashSet::new();
+    // Route Ctrl-C through our atomic counter rather than killing the tracer.
+    // The counter and handler are scoped to this run: reset it on entry and
+    // restore the previous handler on exit (the guard fires on every path).
+    INTERRUPTS.store(0, Ordering::SeqCst);
+    let action = signal::SigAction::new(signal::SigHandler::Handler(handle_sigint),
+                                        signal::SaFlags::empty(),
+                                        signal::SigSet::empty());
+    let _sigint = SigintGuard {
+        previous: unsafe { signal::sigaction(signal::SIGINT, &action)? },
+    };
     loop {
-        let step = wait_state()?;
+        // Honour any pending interrupt before we arm more breakpoints or block
+        // in another wait. First press drains partial coverage, second aborts.
+        match INTERRUPTS.load(Ordering::SeqCst) {
+            0 => {},
+            1 => {
+                detach_tree(pid, &process_tree, breakpoints);
+                break;
+            },
+            _ => {
+                kill_tree(pid, &process_tree);
+                break;
+            },
+        }
+        // Reset the deadline on every iteration: the timeout guards against a
+        // lack of *progress*, so each observed state transition buys the test
+        // another full window.
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let step = match wait_state(deadline) {
+            Ok(step) => step,
+            Err(RunError::Timeout) => {
+                // No state change within the window: tear the tree down and
+                // surface the timeout. The caller still has whatever hits were
+                // accumulated in `traces` before the hang.
+                kill_tree(pid, &process_tree);
+                return Err(RunError::Timeout);
+            }
+            // A SIGINT interrupts the blocking wait; loop back so the interrupt
+            // counter above gets a chance to act on it.
+            Err(RunError::Nix(NixErr::Sys(Errno::EINTR))) => continue,
+            Err(e) => return Err(e),
+        };
         match step.wait_signal {
             WaitStatus::Exited(child, sig) => {
+                threads.remove(&child);
                 for (_, ref mut value) in breakpoints.iter_mut() {
-                    value.thread_killed(child); 
+                    value.thread_killed(child);
                 }
                 res = sig;
                 // If test executable exiting break, else continue the program
@@ -122,86 +388,114 @@ pub fn run_function(pid: Pid,
                     break;
                 } else {
                     // The err will be no child process and means test is over.
-                    let _ =continue_exec(pid, None);
+                    let _ = resume(pid, None, trace_syscalls);
+                }
+            },
+            // Syscall-entry/exit stop (WSTOPSIG was SIGTRAP|0x80), decoded from
+            // the raw status word in `RunState::from_raw`.
+            WaitStatus::Stopped(child, _) if step.syscall_stop => {
+                if !check_parents(&ignored_parents, child, &process_tree) {
+                    handle_syscall(child, syscalls);
                 }
+                resume(child, None, trace_syscalls)?;
             },
             WaitStatus::Stopped(child, signal::SIGTRAP) => {
-                if check_parents(&ignored_parents, child) {
-                    continue_exec(child, Some(signal::SIGTRAP))?;
+                if check_parents(&ignored_parents, child, &process_tree) {
+                    resume(child, Some(signal::SIGTRAP), trace_syscalls)?;
                 } else {
-                    handle_trap(child, no_count, thread_count, &mut unwarned, 
+                    handle_trap(child, no_count, trace_syscalls, &threads,
                                 traces, breakpoints)?;
                 }
             },
             WaitStatus::Stopped(child, signal::SIGSTOP) => {
-                if check_parents(&ignored_parents, child) {
-                    continue_exec(child, Some(signal::SIGSTOP))?;
+                if pending_forks.remove(&child) {
+                    // Child has stopped after the fork; re-arm the breakpoints
+                    // in its address space so its hits are counted too.
+                    for bp in breakpoints.values_mut() {
+                        bp.enable(child)?;
+                    }
+                    resume(child, None, trace_syscalls)?;
+                } else if pending_detach.remove(&child) {
+                    // Child has stopped after the fork; now we can detach it so
+                    // it runs on untraced.
+                    detach_child(child)?;
+                } else if check_parents(&ignored_parents, child, &process_tree) {
+                    resume(child, Some(signal::SIGSTOP), trace_syscalls)?;
                 } else {
-                    continue_exec(child, None)?;
+                    resume(child, None, trace_syscalls)?;
                 }
             },
             WaitStatus::Stopped(child, signal::SIGSEGV) => {
-                if check_parents(&ignored_parents, child) {
-                    continue_exec(child, Some(signal::SIGSEGV))?;
+                if check_parents(&ignored_parents, child, &process_tree) {
+                    resume(child, Some(signal::SIGSEGV), trace_syscalls)?;
                 } else {
                     break;
                 }
             },
             WaitStatus::Stopped(child, sig) => {
-                let s = if forward_signals | check_parents(&ignored_parents, child) {
+                let s = if forward_signals | check_parents(&ignored_parents, child, &process_tree) {
                     println!("Forwarding");
                     Some(sig)
                 } else {
                     None
                 };
-                continue_exec(child, s)?;
+                resume(child, s, trace_syscalls)?;
             },
             WaitStatus::PtraceEvent(child, signal::SIGTRAP, PTRACE_EVENT_CLONE) => {
-                if check_parents(&ignored_parents, child) {
-                    continue_exec(child, Some(signal::SIGTRAP))?;
-                } else if get_event_data(child).is_ok() {
-                    thread_count += 1;
-                    continue_exec(child, None)?;
-                }                 
-            },
-            WaitStatus::PtraceEvent(child, signal::SIGTRAP, PTRACE_EVENT_FORK) => {
-                let sig = if check_parents(&ignored_parents, child) {
-                    Some(signal::SIGTRAP)
-                } else {
-                    None
-                };
-                continue_exec(child, sig)?;
+                if check_parents(&ignored_parents, child, &process_tree) {
+                    resume(child, Some(signal::SIGTRAP), trace_syscalls)?;
+                } else if let Ok(t) = get_event_data(child) {
+                    let cloned = Pid::from_raw(t as pid_t);
+                    threads.insert(cloned);
+                    process_tree.insert(cloned, child);
+                    resume(child, None, trace_syscalls)?;
+                }
             },
+            WaitStatus::PtraceEvent(child, signal::SIGTRAP, PTRACE_EVENT_FORK) |
             WaitStatus::PtraceEvent(child, signal::SIGTRAP, PTRACE_EVENT_VFORK) => {
-                let sig = if check_parents(&ignored_parents, child) {
-                    Some(signal::SIGTRAP)
-                } else {
-                    None
-                };
-                continue_exec(child, sig)?;
+                if check_parents(&ignored_parents, child, &process_tree) {
+                    resume(child, Some(signal::SIGTRAP), trace_syscalls)?;
+                } else if let Ok(t) = get_event_data(child) {
+                    let forked = Pid::from_raw(t as pid_t);
+                    process_tree.insert(forked, child);
+                    // The child is not stopped yet, so neither re-arming nor
+                    // detaching can touch it here (both fail with ESRCH);
+                    // defer until we observe its initial SIGSTOP.
+                    if follow_forks {
+                        pending_forks.insert(forked);
+                    } else {
+                        pending_detach.insert(forked);
+                    }
+                    resume(child, None, trace_syscalls)?;
+                }
             },
             WaitStatus::PtraceEvent(child, signal::SIGTRAP, PTRACE_EVENT_EXEC) => {
                 println!("Exec event {:?}", get_event_data(child));
                 ignored_parents.insert(child);
                 detach_child(child)?;
-                   // continue_exec(child, Some(signal::SIGTRAP))?; // <- this right?
+                   // resume(child, Some(signal::SIGTRAP), trace_syscalls)?; // <- this right?
             },
             WaitStatus::PtraceEvent(child, signal::SIGTRAP, PTRACE_EVENT_EXIT) => {
-                let sig = if check_parents(&ignored_parents, child) {
+                let sig = if check_parents(&ignored_parents, child, &process_tree) {
                     Some(signal::SIGTRAP)
                 } else {
-                    thread_count -= 1;
+                    // Thread is on its way out; drop it from the live set and
+                    // from every breakpoint's per-thread bookkeeping.
+                    threads.remove(&child);
+                    for value in breakpoints.values_mut() {
+                        value.thread_killed(child);
+                    }
                     None
                 };
-                continue_exec(child, sig)?;
+                resume(child, sig, trace_syscalls)?;
             },
             WaitStatus::Signaled(child, signal::SIGTRAP, true) => {
-                let sig = if check_parents(&ignored_parents, child) {
+                let sig = if check_parents(&ignored_parents, child, &process_tree) {
                     Some(signal::SIGTRAP)
                 } else {
                     None
                 };
-                continue_exec(child, sig)?;
+                resume(child, sig, trace_syscalls)?;
             },
             s => {
                 println!("Unexpected stop {:?}", s);
@@ -211,3 +505,36 @@ pub fn run_function(pid: Pid,
     }
     Ok(res)
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_parents_walks_tree() {
+        // 3 -> 2 -> 1, with 1 the only ignored parent.
+        let mut tree = HashMap::new();
+        tree.insert(Pid::from_raw(2), Pid::from_raw(1));
+        tree.insert(Pid::from_raw(3), Pid::from_raw(2));
+        let mut parents = HashSet::new();
+        parents.insert(Pid::from_raw(1));
+
+        assert!(check_parents(&parents, Pid::from_raw(1), &tree));
+        assert!(check_parents(&parents, Pid::from_raw(3), &tree));
+        assert!(!check_parents(&parents, Pid::from_raw(4), &tree));
+    }
+
+    #[test]
+    fn folds_repeated_syscalls() {
+        let mut syscalls = Vec::new();
+        record_syscall(&mut syscalls, 1, 0x1000, None);
+        record_syscall(&mut syscalls, 1, 0x1000, None);
+        record_syscall(&mut syscalls, 2, 0x1000, None);
+        record_syscall(&mut syscalls, 1, 0x2000, None);
+        assert_eq!(syscalls.len(), 3);
+        assert_eq!(syscalls[0].count, 2);
+        assert_eq!(syscalls[1].nr, 2);
+        assert_eq!(syscalls[2].origin_rip, 0x2000);
+    }
+}